@@ -0,0 +1,133 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The allocator-free core of `std::error`.
+//!
+//! `Error`, `Chain`, `FromError` and the `TypeId`-based downcasting impls
+//! below need nothing beyond `core`, so they live here rather than in
+//! `libstd`, letting `no_std` crates use them directly. `libstd::error`
+//! re-exports this module's items and layers the heap-dependent pieces
+//! (`AnyError`, `Context`, backtrace capture) on top.
+
+use any::{AnyRefExt, AnyMutRefExt};
+use fmt::{Debug, Display};
+use mem::{transmute, transmute_copy};
+use option::{Option, Some, None};
+use raw::TraitObject;
+use intrinsics::TypeId;
+
+/// Base functionality for all errors in Rust.
+pub trait Error: Debug + Display {
+    /// The lower-level source of this error, if any.
+    fn source(&self) -> Option<&(Error + 'static)> { None }
+
+    /// Returns the `TypeId` of `self`.
+    ///
+    /// Not intended to be overridden; used by the downcasting impls below in
+    /// place of the old `Any` supertrait so that non-`'static` `Error` impls
+    /// remain possible.
+    #[doc(hidden)]
+    fn get_type_id(&self) -> TypeId where Self: 'static {
+        TypeId::of::<Self>()
+    }
+}
+
+/// An iterator over an `Error` and the chain of `source`s that produced it.
+///
+/// Created by `Error::iter_chain`.
+pub struct Chain<'a> {
+    current: Option<&'a (Error + 'static)>,
+}
+
+impl<'a> Iterator<&'a (Error + 'static)> for Chain<'a> {
+    fn next(&mut self) -> Option<&'a (Error + 'static)> {
+        let current = self.current;
+        self.current = current.and_then(|e| e.source());
+        current
+    }
+}
+
+impl Error + 'static {
+    /// Returns an iterator starting with `self` and yielding each error in
+    /// the `source` chain until one is reached that has no further source.
+    ///
+    /// This is an inherent method on the `Error + 'static` trait object
+    /// type, not a method of the `Error` trait itself, so calling it on a
+    /// concrete error value `e` requires an explicit coercion to the trait
+    /// object first, e.g. `(&e as &(Error + 'static)).iter_chain()`. Making
+    /// it a trait method instead would add `Self: 'static` to every `Error`
+    /// impl, which would rule out the non-`'static` errors `Error` is
+    /// otherwise happy to support.
+    pub fn iter_chain<'a>(&'a self) -> Chain<'a> {
+        Chain { current: Some(self) }
+    }
+}
+
+/// A trait for types that can be converted from a given error type `E`.
+pub trait FromError<E> {
+    /// Perform the conversion.
+    fn from_error(err: E) -> Self;
+}
+
+// Any type is convertable from itself
+impl<E> FromError<E> for E {
+    fn from_error(err: E) -> E {
+        err
+    }
+}
+
+// Note: the definitions below are copied from core::any, and should be unified
+// as soon as possible.
+
+impl<'a> AnyRefExt<'a> for &'a (Error + 'static) {
+    #[inline]
+    fn is<T: 'static>(self) -> bool {
+        // Get TypeId of the type this function is instantiated with
+        let t = TypeId::of::<T>();
+
+        // Get TypeId of the type in the trait object
+        let boxed = self.get_type_id();
+
+        // Compare both TypeIds on equality
+        t == boxed
+    }
+
+    #[inline]
+    fn downcast_ref<T: 'static>(self) -> Option<&'a T> {
+        if self.is::<T>() {
+            unsafe {
+                // Get the raw representation of the trait object
+                let to: TraitObject = transmute_copy(&self);
+
+                // Extract the data pointer
+                Some(transmute(to.data))
+            }
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a> AnyMutRefExt<'a> for &'a mut (Error + 'static) {
+    #[inline]
+    fn downcast_mut<T: 'static>(self) -> Option<&'a mut T> {
+        if self.is::<T>() {
+            unsafe {
+                // Get the raw representation of the trait object
+                let to: TraitObject = transmute_copy(&self);
+
+                // Extract the data pointer
+                Some(transmute(to.data))
+            }
+        } else {
+            None
+        }
+    }
+}
@@ -13,28 +13,58 @@
 //! # The `Error` trait
 //!
 //! `Error` is a trait representing the basic expectations for error values,
-//! i.e. values of type `E` in `Result<T, E>`. At a minimum, errors must provide
-//! a description, but they may optionally provide additional detail and cause
-//! chain information:
+//! i.e. values of type `E` in `Result<T, E>`. Errors must describe
+//! themselves through the `Display` and `Debug` traits, and may optionally
+//! provide cause chain information:
 //!
 //! ```
-//! pub trait Error: Send + Any {
-//!     fn description(&self) -> &str;
+//! use std::fmt::{Debug, Display};
 //!
-//!     fn detail(&self) -> Option<String> { None }
-//!     fn cause(&self) -> Option<&Error> { None }
+//! pub trait Error: Debug + Display {
+//!     fn source(&self) -> Option<&(Error + 'static)> { None }
 //! }
 //! ```
 //!
-//! The `cause` method is generally used when errors cross "abstraction
-//! boundaries", i.e.  when a one module must report an error that is "caused"
-//! by an error from a lower-level module. This setup makes it possible for the
-//! high-level module to provide its own errors that do not commit to any
+//! The `source` method is generally used when errors cross "abstraction
+//! boundaries", i.e. when one module must report an error that is "caused"
+//! by an error from a lower-level module. This setup makes it possible for
+//! the high-level module to provide its own errors that do not commit to any
 //! particular implementation, but also reveal some of its implementation for
-//! debugging via `cause` chains.
+//! debugging via `source` chains. Calling `iter_chain()` walks the whole
+//! chain without requiring callers to hand-write the `while let Some(..)`
+//! loop themselves.
 //!
-//! The trait inherits from `Any` to allow *downcasting*: converting from a
-//! trait object to a specific concrete type when applicable.
+//! `Any`-style *downcasting* -- converting from a trait object back to a
+//! specific concrete type when applicable -- is implemented directly on
+//! `Error` trait objects below, rather than by requiring `Error: Any`, so
+//! that types whose `Error` impl is not `'static` are still free to
+//! implement the trait.
+//!
+//! `Error` itself, `Chain`, `FromError` and the downcasting impls live in
+//! `core::error` and are re-exported here unchanged; see that module's
+//! documentation for what `no_std` crates get without linking `libstd`.
+//!
+//! # The `AnyError` type
+//!
+//! `AnyError` is an owned, type-erased `Error` for crossing abstraction
+//! boundaries without committing to a single concrete error type. It is
+//! built around a hand-rolled vtable stored in the error's own heap
+//! allocation rather than in a fat pointer, which keeps `AnyError` itself a
+//! single pointer wide and cheap to move around in a `Result<T, AnyError>`.
+//!
+//! The `Context` extension trait builds on `AnyError` to let callers
+//! annotate a `Result`'s error with a message as it crosses an abstraction
+//! boundary, e.g. `config::load().context("reading config")`, without
+//! losing the ability to downcast back to the original error.
+//!
+//! `AnyError` also captures a `Backtrace` every time one is created,
+//! including when `context` wraps an error that is already an `AnyError`;
+//! an inner `AnyError`'s backtrace is not reused. Capture is skipped unless backtraces have been opted into (see
+//! `backtrace_enabled`), so errors that are never erased into an
+//! `AnyError`, or that opt out of backtraces entirely, don't pay for it.
+//! There is deliberately no `Error::backtrace` hook for `AnyError` to defer
+//! to instead: `Error` lives in `core`, which has no `Backtrace` type to
+//! name, so capture is always `AnyError`'s own responsibility.
 //!
 //! # The `FromError` trait
 //!
@@ -45,93 +75,451 @@
 //!
 //! The main use of this trait is in the `try!` macro, which uses it to
 //! automatically convert a given error to the error specified in a function's
-//! return type.
+//! return type. This includes a `FromError<E> for Box<Error + Send>` impl
+//! for any `E: Error + Send + 'static` other than `Box<Error + Send>` itself
+//! and types that contain one, so functions returning
+//! `Result<T, Box<Error + Send>>` can coalesce heterogeneous library errors
+//! with `try!` instead of writing `map_err` at every call site.
 
-use any::{Any, AnyRefExt, AnyMutRefExt};
-use mem::{transmute, transmute_copy};
-use option::{Option, Some, None};
-use raw::TraitObject;
-use intrinsics::TypeId;
+use alloc::heap;
+use boxed::Box;
+use fmt;
+use fmt::{Debug, Display};
+use io::MemWriter;
 use kinds::Send;
+use mem;
+use option::{Option, Some, None};
+use os;
+use ptr;
+use result::{Result, Ok, Err};
+use rt::backtrace;
+use str::StrSlice;
 use string::String;
+use intrinsics::TypeId;
 
-/// Base functionality for all errors in Rust.
-pub trait Error: Send + Any {
-    /// A short description of the error; usually a static string.
-    fn description(&self) -> &str;
+pub use core::error::{Chain, Error, FromError};
 
-    /// A detailed description of the error, usually including dynamic information.
-    fn detail(&self) -> Option<String> { None }
+/// A captured stack trace.
+///
+/// This is a plain struct defined here, not a type pulled in from some
+/// future `std::backtrace` module -- no such module exists in this tree.
+/// Symbolizing and walking the stack is the runtime's job already -- the
+/// same `rt::backtrace::write` the unhandled-panic handler calls into for
+/// `RUST_BACKTRACE=1` -- so `Backtrace::new` just reuses it, capturing its
+/// formatted output into an owned `String` instead of printing it.
+pub struct Backtrace {
+    repr: String,
+}
 
-    /// The lower-level cause of this error, if any.
-    fn cause(&self) -> Option<&Error> { None }
+impl Backtrace {
+    /// Captures the current call stack, if supported on this platform.
+    pub fn new() -> Backtrace {
+        let mut w = MemWriter::new();
+        let _ = backtrace::write(&mut w);
+        Backtrace { repr: String::from_utf8_lossy(w.get_ref()).into_owned() }
+    }
 }
 
-/// A trait for types that can be converted from a given error type `E`.
-pub trait FromError<E> {
-    /// Perform the conversion.
-    fn from_error(err: E) -> Self;
+impl Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.repr.as_slice())
+    }
 }
 
-// Any type is convertable from itself
-impl<E> FromError<E> for E {
-    fn from_error(err: E) -> E {
-        err
+impl Debug for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Display::fmt(self, f)
     }
 }
 
-// FIXME (#https://github.com/rust-lang/rust/pull/17669/): Add this once multidispatch lands
-// impl<E: Error> FromError<E> for Box<Error> {
-//     fn from_err(err: E) -> Box<Error> {
-//         box err as Box<Error>
-//     }
-// }
+/// The fixed-size header stored at the front of the heap allocation backing
+/// an `AnyError`, followed immediately by the erased error value itself.
+///
+/// `#[repr(C)]` pins the field order so that the `vtable`/`type_id`/
+/// `backtrace` prefix is at the same offset whether the allocation is
+/// viewed through `ErrorImpl<E>` (the concrete type it was built with) or
+/// `ErrorImpl<()>` (the erased type `AnyError` actually stores a pointer
+/// to) -- none of those fields' types depend on `E`.
+#[repr(C)]
+struct ErrorImpl<E> {
+    vtable: &'static ErrorVtable,
+    type_id: TypeId,
+    backtrace: Option<Backtrace>,
+    error: E,
+}
 
-// Note: the definitions below are copied from core::any, and should be unified
-// as soon as possible.
+/// Hand-built vtable for a type-erased `Error`, generated once per concrete
+/// error type by `vtable::<E>()`.
+///
+/// A trait object reference (`&Error`) is a fat pointer: data pointer plus
+/// vtable pointer. Storing the vtable pointer in the allocation's header
+/// instead, as done here, means `AnyError` itself only has to carry the one
+/// data pointer.
+struct ErrorVtable {
+    object_drop: unsafe fn(*mut ErrorImpl<()>),
+    object_drop_front: unsafe fn(*mut ErrorImpl<()>),
+    object_fmt_display: unsafe fn(*const ErrorImpl<()>, &mut fmt::Formatter) -> fmt::Result,
+    object_fmt_debug: unsafe fn(*const ErrorImpl<()>, &mut fmt::Formatter) -> fmt::Result,
+    object_source: unsafe fn(*const ErrorImpl<()>) -> Option<&'static (Error + 'static)>,
+    object_backtrace: unsafe fn(*const ErrorImpl<()>) -> Option<&'static Backtrace>,
+}
 
-impl<'a> AnyRefExt<'a> for &'a Error {
-    #[inline]
-    fn is<T: 'static>(self) -> bool {
-        // Get TypeId of the type this function is instantiated with
-        let t = TypeId::of::<T>();
+/// Size and alignment of the allocation backing an `ErrorImpl<E>`, computed
+/// once so construction, `object_drop` and `object_drop_front` all agree on
+/// exactly what `alloc::heap::{allocate, deallocate}` were called with.
+fn layout_of<E>() -> (uint, uint) {
+    (mem::size_of::<ErrorImpl<E>>(), mem::align_of::<ErrorImpl<E>>())
+}
 
-        // Get TypeId of the type in the trait object
-        let boxed = self.get_type_id();
+unsafe fn object_drop<E>(e: *mut ErrorImpl<()>) {
+    // Drops both the contained `error` and the allocation it lives in.
+    // `alloc::heap`'s `allocate`/`deallocate` are used directly here, rather
+    // than routing construction and teardown through `Box`, specifically so
+    // `object_drop_front` below can free the allocation without re-dropping
+    // `error` -- without reaching for `mem::ManuallyDrop`, which doesn't
+    // exist in this tree.
+    let e = e as *mut ErrorImpl<E>;
+    let (size, align) = layout_of::<E>();
+    drop(ptr::read(e));
+    heap::deallocate(e as *mut u8, size, align);
+}
+
+unsafe fn object_drop_front<E>(e: *mut ErrorImpl<()>) {
+    // Used by `downcast`, after `error` has already been read out of the
+    // allocation by value. Only the `backtrace` field and the allocation
+    // itself need freeing here; running `E`'s destructor again over bits
+    // that no longer belong to it would double-drop.
+    let e = e as *mut ErrorImpl<E>;
+    let (size, align) = layout_of::<E>();
+    drop(ptr::read(&(*e).backtrace));
+    heap::deallocate(e as *mut u8, size, align);
+}
+
+unsafe fn object_fmt_display<E: Display>(e: *const ErrorImpl<()>, f: &mut fmt::Formatter) -> fmt::Result {
+    Display::fmt(&(*(e as *const ErrorImpl<E>)).error, f)
+}
+
+unsafe fn object_fmt_debug<E: Debug>(e: *const ErrorImpl<()>, f: &mut fmt::Formatter) -> fmt::Result {
+    Debug::fmt(&(*(e as *const ErrorImpl<E>)).error, f)
+}
 
-        // Compare both TypeIds on equality
-        t == boxed
+unsafe fn object_source<E: Error + 'static>(e: *const ErrorImpl<()>) -> Option<&'static (Error + 'static)> {
+    let error: &(Error + 'static) = match (*(e as *const ErrorImpl<E>)).error.source() {
+        Some(source) => source,
+        None => return None,
+    };
+    Some(mem::transmute(error))
+}
+
+unsafe fn object_backtrace(e: *const ErrorImpl<()>) -> Option<&'static Backtrace> {
+    match (*e).backtrace {
+        Some(ref backtrace) => Some(mem::transmute(backtrace)),
+        None => None,
     }
+}
 
-    #[inline]
-    fn downcast_ref<T: 'static>(self) -> Option<&'a T> {
-        if self.is::<T>() {
-            unsafe {
-                // Get the raw representation of the trait object
-                let to: TraitObject = transmute_copy(&self);
+fn vtable<E: Error + Send + 'static>() -> &'static ErrorVtable {
+    &ErrorVtable {
+        object_drop: object_drop::<E>,
+        object_drop_front: object_drop_front::<E>,
+        object_fmt_display: object_fmt_display::<E>,
+        object_fmt_debug: object_fmt_debug::<E>,
+        object_source: object_source::<E>,
+        object_backtrace: object_backtrace,
+    }
+}
 
-                // Extract the data pointer
-                Some(transmute(to.data))
-            }
+/// Whether backtrace capture is enabled, mirroring the `RUST_BACKTRACE`
+/// environment gate the runtime's own panic machinery already honors, so
+/// that wrapping an error into an `AnyError` pays nothing for capture
+/// unless a backtrace was actually asked for.
+fn backtrace_enabled() -> bool {
+    match os::getenv("RUST_BACKTRACE") {
+        Some(ref val) if val.as_slice() != "0" => true,
+        _ => false,
+    }
+}
+
+/// A type-erased, owned error, for propagating arbitrary errors across
+/// abstraction boundaries without committing a function's signature to one
+/// concrete error type.
+///
+/// Despite owning a heap-allocated error of arbitrary size, `AnyError` is
+/// the width of a single pointer: rather than a `Box<Error + Send>`'s usual
+/// fat pointer (data pointer plus vtable pointer), it stores a thin pointer
+/// to an allocation whose header carries a hand-built `ErrorVtable`,
+/// followed by the concrete error value. This keeps `AnyError` cheap to
+/// move and to store inline in a `Result<T, AnyError>`.
+pub struct AnyError {
+    inner: *mut ErrorImpl<()>,
+}
+
+unsafe impl Send for AnyError {}
+
+impl AnyError {
+    /// Erases `error`'s concrete type, producing an owned `AnyError`.
+    pub fn new<E: Error + Send + 'static>(error: E) -> AnyError {
+        let backtrace = if backtrace_enabled() {
+            Some(Backtrace::new())
         } else {
             None
+        };
+        unsafe {
+            let (size, align) = layout_of::<E>();
+            let ptr = heap::allocate(size, align) as *mut ErrorImpl<E>;
+            ptr::write(ptr, ErrorImpl {
+                vtable: vtable::<E>(),
+                type_id: TypeId::of::<E>(),
+                backtrace: backtrace,
+                error: error,
+            });
+            AnyError { inner: ptr as *mut ErrorImpl<()> }
         }
     }
-}
 
-impl<'a> AnyMutRefExt<'a> for &'a mut Error {
-    #[inline]
-    fn downcast_mut<T: 'static>(self) -> Option<&'a mut T> {
-        if self.is::<T>() {
-            unsafe {
-                // Get the raw representation of the trait object
-                let to: TraitObject = transmute_copy(&self);
+    /// Returns the backtrace captured when this `AnyError` was created, if
+    /// any. If this `AnyError` wraps another one (e.g. via `context`), the
+    /// inner one's backtrace is not consulted.
+    pub fn backtrace(&self) -> Option<&Backtrace> {
+        unsafe { (self.vtable().object_backtrace)(self.inner) }
+    }
 
-                // Extract the data pointer
-                Some(transmute(to.data))
-            }
+    fn vtable(&self) -> &'static ErrorVtable {
+        unsafe { (*self.inner).vtable }
+    }
+
+    /// Returns `true` if the erased error is of type `E`.
+    pub fn is<E: Error + 'static>(&self) -> bool {
+        TypeId::of::<E>() == unsafe { (*self.inner).type_id }
+    }
+
+    /// Returns a reference to the erased error if it is of type `E`.
+    pub fn downcast_ref<E: Error + 'static>(&self) -> Option<&E> {
+        if self.is::<E>() {
+            unsafe { Some(&(*(self.inner as *const ErrorImpl<E>)).error) }
         } else {
             None
         }
     }
+
+    /// Attempts to downcast back to the concrete error type `E`, returning
+    /// `self` unchanged in `Err` if the erased error is not of that type.
+    pub fn downcast<E: Error + 'static>(self) -> Result<E, AnyError> {
+        if self.is::<E>() {
+            unsafe {
+                // Don't run `self`'s `Drop` impl: from here on,
+                // `object_drop_front` takes over responsibility for freeing
+                // the allocation, since `error` below is moved out of it.
+                let inner = self.inner;
+                mem::forget(self);
+                let ptr = inner as *mut ErrorImpl<E>;
+                let error = ptr::read(&(*ptr).error);
+                ((*inner).vtable.object_drop_front)(inner);
+                Ok(error)
+            }
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl Drop for AnyError {
+    fn drop(&mut self) {
+        unsafe { (self.vtable().object_drop)(self.inner); }
+    }
+}
+
+impl Display for AnyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        unsafe { (self.vtable().object_fmt_display)(self.inner, f) }
+    }
+}
+
+impl Debug for AnyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        unsafe { (self.vtable().object_fmt_debug)(self.inner, f) }
+    }
+}
+
+impl Error for AnyError {
+    fn source(&self) -> Option<&(Error + 'static)> {
+        unsafe { (self.vtable().object_source)(self.inner) }
+    }
+}
+
+/// Wraps a lower-level `error` with a `context` message describing what was
+/// being attempted when it occurred. `Display`s as the context message, but
+/// keeps the original `error` reachable through `source`.
+struct ContextError<C, E> {
+    context: C,
+    error: E,
+}
+
+impl<C: Display, E> Display for ContextError<C, E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Display::fmt(&self.context, f)
+    }
+}
+
+impl<C: Display, E: Debug> Debug for ContextError<C, E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{}", self.context));
+        try!(write!(f, "\n\nCaused by:\n    {:?}", self.error));
+        Ok(())
+    }
+}
+
+impl<C: Display + 'static, E: Error + 'static> Error for ContextError<C, E> {
+    fn source(&self) -> Option<&(Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Extension trait for attaching a contextual message to a `Result`'s error,
+/// turning it into an `AnyError` in the process.
+///
+/// Each `.context(..)` call pushes one more link onto the `source` chain, so
+/// a failure can be annotated as it crosses module boundaries while the
+/// original, lower-level error remains reachable for downcasting.
+///
+/// Like `AnyError` itself, `Context` is an unconditional part of `libstd`:
+/// it needs `AnyError`'s heap allocation, so it lives here rather than in
+/// `core::error`, but nothing about it is optional within `libstd`.
+pub trait Context<T, E> {
+    /// Wraps the error, if any, with `context`.
+    fn context<C>(self, context: C) -> Result<T, AnyError>
+        where C: Display + Send + 'static;
+
+    /// Wraps the error, if any, with a context computed lazily from `f`.
+    ///
+    /// Useful when the context message is expensive to build, since `f` is
+    /// only called on the error path.
+    fn with_context<C, F>(self, f: F) -> Result<T, AnyError>
+        where C: Display + Send + 'static, F: FnOnce() -> C;
+}
+
+impl<T, E: Error + Send + 'static> Context<T, E> for Result<T, E> {
+    fn context<C>(self, context: C) -> Result<T, AnyError>
+        where C: Display + Send + 'static
+    {
+        self.map_err(|error| AnyError::new(ContextError { context: context, error: error }))
+    }
+
+    fn with_context<C, F>(self, f: F) -> Result<T, AnyError>
+        where C: Display + Send + 'static, F: FnOnce() -> C
+    {
+        self.map_err(|error| AnyError::new(ContextError { context: f(), error: error }))
+    }
+}
+
+// The reflexive `impl<E> FromError<E> for E` in `core::error` already covers
+// `FromError<Box<Error + Send>> for Box<Error + Send>`, so a plain
+// `impl<E: Error> FromError<E> for Box<Error + Send>` would overlap with it
+// at `E = Box<Error + Send>` (true multidispatch -- distinguishing impls by
+// whether `E` also happens to implement `Error` -- is still blocked on
+// specialization, see rust-lang/rust#17669). `NotBoxedError` carves that one
+// case back out using a negative impl instead. Because `NotBoxedError` is a
+// defaulted trait, the negative impl also excludes every type that contains
+// a `Box<Error + Send>`; all other `Error` types get `try!`-friendly
+// coercion into a `Box<Error + Send>`.
+// This impl lives here, rather than alongside the reflexive one in
+// `core::error`, because `Box` needs an allocator. It's compiled
+// unconditionally, same as the rest of this file -- there's no "std"
+// cargo feature in this build for it to hide behind.
+#[doc(hidden)]
+trait NotBoxedError {}
+impl NotBoxedError for .. {}
+impl !NotBoxedError for Box<Error + Send> {}
+
+impl<E: Error + Send + 'static + NotBoxedError> FromError<E> for Box<Error + Send> {
+    fn from_error(err: E) -> Box<Error + Send> {
+        box err as Box<Error + Send>
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AnyError, Error};
+    use fmt;
+    use fmt::{Debug, Display};
+    use option::{Option, Some, None};
+    use result::{Ok, Err};
+    use string::String;
+
+    struct Simple {
+        msg: String,
+    }
+
+    impl Debug for Simple {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "Simple({})", self.msg)
+        }
+    }
+
+    impl Display for Simple {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{}", self.msg)
+        }
+    }
+
+    impl Error for Simple {}
+
+    struct Wrapping {
+        inner: Simple,
+    }
+
+    impl Debug for Wrapping {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "Wrapping({:?})", self.inner)
+        }
+    }
+
+    impl Display for Wrapping {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "wrapped")
+        }
+    }
+
+    impl Error for Wrapping {
+        fn source(&self) -> Option<&(Error + 'static)> {
+            Some(&self.inner)
+        }
+    }
+
+    #[test]
+    fn downcast_round_trip() {
+        let any = AnyError::new(Simple { msg: "boom".to_string() });
+        assert!(any.is::<Simple>());
+        assert!(!any.is::<Wrapping>());
+        let simple = any.downcast::<Simple>().ok().unwrap();
+        assert_eq!(simple.msg.as_slice(), "boom");
+    }
+
+    #[test]
+    fn failed_downcast_hands_back_a_usable_self() {
+        let any = AnyError::new(Simple { msg: "boom".to_string() });
+        let any = match any.downcast::<Wrapping>() {
+            Ok(_) => panic!("downcast to the wrong type should have failed"),
+            Err(any) => any,
+        };
+        // If `downcast`'s failure path had corrupted or freed the
+        // allocation, either of these would crash or return garbage.
+        assert_eq!(any.to_string(), "boom".to_string());
+        assert_eq!(any.downcast::<Simple>().ok().unwrap().msg.as_slice(), "boom");
+    }
+
+    #[test]
+    fn source_chain_walks_to_the_root() {
+        let wrapped = Wrapping { inner: Simple { msg: "root".to_string() } };
+        let mut chain = (&wrapped as &(Error + 'static)).iter_chain();
+        assert_eq!(chain.next().unwrap().to_string(), "wrapped".to_string());
+        assert_eq!(chain.next().unwrap().to_string(), "root".to_string());
+        assert!(chain.next().is_none());
+    }
+
+    #[test]
+    fn any_error_display_matches_the_wrapped_error() {
+        let any = AnyError::new(Simple { msg: "boom".to_string() });
+        assert_eq!(any.to_string(), "boom".to_string());
+    }
 }